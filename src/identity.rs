@@ -0,0 +1,47 @@
+//! Platform-specific file identity, used to recognize when two `DirEntry`s
+//! refer to the same file (finding the root entry, detecting symlink loops)
+//! and to tell which device a file lives on (`same_file_system`).
+
+use std::fs::Metadata;
+
+/// Uniquely identifies a file on whatever volume/device it lives on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct FileId {
+    pub(crate) device: u64,
+    pub(crate) file: u64,
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::FileId;
+    use std::fs::Metadata;
+    use std::os::unix::fs::MetadataExt;
+
+    pub(crate) fn file_id(metadata: &Metadata) -> FileId {
+        FileId {
+            device: metadata.dev(),
+            file: metadata.ino(),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::FileId;
+    use std::fs::Metadata;
+    use std::os::windows::fs::MetadataExt;
+
+    pub(crate) fn file_id(metadata: &Metadata) -> FileId {
+        FileId {
+            // `Metadata::volume_serial_number`/`file_index` are themselves
+            // backed by the `BY_HANDLE_FILE_INFORMATION` the file's handle
+            // was opened with.
+            device: metadata.volume_serial_number().unwrap_or(0) as u64,
+            file: metadata.file_index().unwrap_or(0),
+        }
+    }
+}
+
+pub(crate) fn file_id(metadata: &Metadata) -> FileId {
+    imp::file_id(metadata)
+}
@@ -1,26 +1,162 @@
-/// TreeWalker: A toy implementation of [`walkdir`](https://crates.io/crates/walkdir)
-///
-/// Different from `walkdir`, [`TreeWalker`] yields [`DirEntry`](https://doc.rust-lang.org/std/fs/struct.DirEntry.html)
-/// type from the standard library each time. This also makes `TreeWalker` unusable
-/// on `/` since you simply can't get a `DirEntry` representing `/`.
-///
-///
-/// The traversal is done is `pre-order`.
+//! TreeWalker: A toy implementation of [`walkdir`](https://crates.io/crates/walkdir)
+//!
+//! Different from `walkdir`, [`TreeWalker`] yields [`DirEntry`](https://doc.rust-lang.org/std/fs/struct.DirEntry.html)
+//! type from the standard library each time. This also makes `TreeWalker` unusable
+//! on `/` since you simply can't get a `DirEntry` representing `/`.
+//!
+//!
+//! The traversal is done in `pre-order` by default; pass `contents_first(true)`
+//! to switch to `post-order`.
+
+mod identity;
 
 use std::{
+    cmp::Ordering,
+    collections::HashSet,
     env::current_dir,
+    error, fmt,
     fs::{metadata, read_dir, DirEntry},
-    io::Result,
-    os::linux::fs::MetadataExt,
+    io,
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
 use path_absolutize::Absolutize;
 
-#[derive(Default, Debug)]
+use identity::{file_id, FileId};
+
+/// The set of [`FileId`]s of the directories on the current root-to-node
+/// path, used to detect symlink loops when `follow_links` is enabled.
+type Ancestors = Rc<HashSet<FileId>>;
+
+/// A predicate consulted before a directory is expanded, as configured by
+/// [`TreeWalkerBuilder::filter_entry`].
+type FilterEntry = Box<dyn FnMut(&DirEntry) -> bool>;
+
+/// A comparator used to sort a directory's children, as configured by
+/// [`TreeWalkerBuilder::sort_by`].
+type SortBy = Box<dyn FnMut(&DirEntry, &DirEntry) -> Ordering>;
+
+#[derive(Default)]
 pub struct TreeWalker {
-    stack: Vec<DirEntry>,
+    stack: Vec<Pending>,
     fatal_error: bool,
+    /// Device id of the root entry passed to [`TreeWalker::new`].
+    root_device: u64,
+    /// When `true`, directories whose device id differs from
+    /// [`Self::root_device`] are yielded but not descended into.
+    same_file_system: bool,
+    /// Entries at this depth (relative to the root, which is depth `0`) are
+    /// yielded but not descended into, so nothing deeper is ever reached.
+    max_depth: Option<usize>,
+    /// Entries shallower than this are traversed but not yielded.
+    min_depth: usize,
+    /// When `true`, a directory's contents are yielded before the directory
+    /// itself (post-order), instead of the default pre-order.
+    contents_first: bool,
+    /// When `true`, symlinked directories are descended into (guarded
+    /// against loops); when `false` (the default) they are yielded as leaves.
+    follow_links: bool,
+    /// Consulted before a directory is expanded; returning `false` prunes it
+    /// (and its whole subtree) from both the output and the traversal.
+    filter_entry: Option<FilterEntry>,
+    /// When set, a directory's children are sorted with this comparator
+    /// before being pushed, making the traversal order deterministic.
+    sort_by: Option<SortBy>,
+}
+
+impl fmt::Debug for TreeWalker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TreeWalker")
+            .field("stack", &self.stack)
+            .field("fatal_error", &self.fatal_error)
+            .field("root_device", &self.root_device)
+            .field("same_file_system", &self.same_file_system)
+            .field("max_depth", &self.max_depth)
+            .field("min_depth", &self.min_depth)
+            .field("contents_first", &self.contents_first)
+            .field("follow_links", &self.follow_links)
+            .field("filter_entry", &self.filter_entry.is_some())
+            .field("sort_by", &self.sort_by.is_some())
+            .finish()
+    }
+}
+
+/// An item sitting on [`TreeWalker`]'s internal stack.
+///
+/// A directory is first popped as `Descend` so its children can be read and
+/// pushed; if `contents_first` is enabled, the directory itself is then
+/// pushed back as `Emit` underneath those children, so it is only handed to
+/// the caller once every child has been.
+#[derive(Debug)]
+enum Pending {
+    Descend(DirEntry, usize, Ancestors),
+    Emit(DirEntry, usize),
+}
+
+/// Errors that can occur while walking a directory tree.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error occurred while reading a directory or its entries'
+    /// metadata.
+    Io(io::Error),
+    /// Following a symlink would re-enter a directory already on the
+    /// current root-to-node path, which would otherwise recurse forever.
+    Loop(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Loop(path) => {
+                write!(f, "filesystem loop detected at {}", path.display())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Loop(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A specialized [`std::result::Result`] type for [`TreeWalker`].
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A [`DirEntry`] paired with its depth relative to the root passed to
+/// [`TreeWalker::new`] (which sits at depth `0`).
+#[derive(Debug)]
+pub struct TreeEntry {
+    entry: DirEntry,
+    depth: usize,
+}
+
+impl TreeEntry {
+    /// The underlying [`DirEntry`].
+    pub fn entry(&self) -> &DirEntry {
+        &self.entry
+    }
+
+    /// Depth of this entry relative to the root, which sits at depth `0`.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Discard the depth and return the underlying [`DirEntry`].
+    pub fn into_inner(self) -> DirEntry {
+        self.entry
+    }
 }
 
 /// Adjust the `length` field of a `PathBuf` to make it become its parent
@@ -53,7 +189,7 @@ fn absolute_path<P: AsRef<Path>>(path: P) -> PathBuf {
 
 impl TreeWalker {
     /// Construct a [`TreeWalker`] instance.
-    pub fn new<P: AsRef<Path>>(start: P) -> Result<Self> {
+    pub fn new<P: AsRef<Path>>(start: P) -> io::Result<Self> {
         let start_metadata = metadata(start.as_ref())?;
 
         let mut walker = TreeWalker::default();
@@ -63,28 +199,177 @@ impl TreeWalker {
         // get start's parent directory
         let parent = cd_to_parent(start);
 
+        let start_id = file_id(&start_metadata);
+
         // iterate over the entries in `parent` to find `start`
         let parent_dir = read_dir(parent.as_path())?;
         for res_item in parent_dir {
             let item = res_item?;
             let item_metadata = item.metadata()?;
-            if item_metadata.st_dev() == start_metadata.st_dev()
-                && item_metadata.st_ino() == start_metadata.st_ino()
-            {
-                // push `start` to the stack
-                walker.stack.push(item);
+            if file_id(&item_metadata) == start_id {
+                // push `start` to the stack at depth 0
+                walker
+                    .stack
+                    .push(Pending::Descend(item, 0, Ancestors::default()));
                 break;
             }
         }
 
         // When used on `/`, this assertion will fail...
         assert_eq!(walker.stack.len(), 1);
+
+        walker.root_device = start_id.device;
+
+        Ok(walker)
+    }
+
+    /// Restrict the traversal to the file system the root entry lives on.
+    ///
+    /// When enabled, a directory whose device id differs from the root's
+    /// (e.g. a bind mount, a network file system, or a `/proc`-style pseudo
+    /// file system) is still yielded, but `TreeWalker` will not descend into
+    /// it. Disabled by default.
+    pub fn same_file_system(mut self, yes: bool) -> Self {
+        self.same_file_system = yes;
+        self
+    }
+
+    /// Do not descend into entries deeper than `depth` (the root is depth
+    /// `0`). Entries at `depth` are still yielded, just not expanded.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Skip entries shallower than `depth` from the output, while still
+    /// traversing through them to reach their descendants.
+    pub fn min_depth(mut self, depth: usize) -> Self {
+        self.min_depth = depth;
+        self
+    }
+
+    /// Yield a directory's contents before the directory itself
+    /// (post-order), instead of the default pre-order. Useful for recursive
+    /// deletion or computing aggregate directory sizes.
+    pub fn contents_first(mut self, yes: bool) -> Self {
+        self.contents_first = yes;
+        self
+    }
+
+    /// Descend into symlinked directories instead of treating them as
+    /// leaves. Guarded against loops: re-entering a directory already on the
+    /// current path yields [`Error::Loop`] instead of recursing. Disabled by
+    /// default.
+    pub fn follow_links(mut self, yes: bool) -> Self {
+        self.follow_links = yes;
+        self
+    }
+}
+
+/// Builds a [`TreeWalker`], mirroring the configuration-object pattern used
+/// by mature directory walkers.
+///
+/// Its centerpiece is [`TreeWalkerBuilder::filter_entry`], which prunes a
+/// directory (and everything under it) from the traversal entirely, rather
+/// than merely filtering it out of the output afterwards.
+#[derive(Default)]
+pub struct TreeWalkerBuilder {
+    start: PathBuf,
+    same_file_system: bool,
+    max_depth: Option<usize>,
+    min_depth: usize,
+    contents_first: bool,
+    follow_links: bool,
+    filter_entry: Option<FilterEntry>,
+    sort_by: Option<SortBy>,
+}
+
+impl TreeWalkerBuilder {
+    /// Start building a [`TreeWalker`] rooted at `start`.
+    pub fn new<P: AsRef<Path>>(start: P) -> Self {
+        Self {
+            start: start.as_ref().to_path_buf(),
+            ..Self::default()
+        }
+    }
+
+    /// See [`TreeWalker::same_file_system`].
+    pub fn same_file_system(mut self, yes: bool) -> Self {
+        self.same_file_system = yes;
+        self
+    }
+
+    /// See [`TreeWalker::max_depth`].
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// See [`TreeWalker::min_depth`].
+    pub fn min_depth(mut self, depth: usize) -> Self {
+        self.min_depth = depth;
+        self
+    }
+
+    /// See [`TreeWalker::contents_first`].
+    pub fn contents_first(mut self, yes: bool) -> Self {
+        self.contents_first = yes;
+        self
+    }
+
+    /// See [`TreeWalker::follow_links`].
+    pub fn follow_links(mut self, yes: bool) -> Self {
+        self.follow_links = yes;
+        self
+    }
+
+    /// Prune a directory (and its whole subtree) from the traversal when
+    /// `predicate` returns `false` for it. Unlike filtering the iterator's
+    /// output afterwards, this avoids ever calling `read_dir` on the pruned
+    /// subtree.
+    ///
+    /// The predicate is only consulted for directory entries, right before
+    /// they would otherwise be expanded.
+    pub fn filter_entry<F>(mut self, predicate: F) -> Self
+    where
+        F: FnMut(&DirEntry) -> bool + 'static,
+    {
+        self.filter_entry = Some(Box::new(predicate));
+        self
+    }
+
+    /// Sort each directory's children with `comparator` before descending
+    /// into them, making the traversal order deterministic instead of
+    /// whatever order `read_dir` happens to return.
+    pub fn sort_by<F>(mut self, comparator: F) -> Self
+    where
+        F: FnMut(&DirEntry, &DirEntry) -> Ordering + 'static,
+    {
+        self.sort_by = Some(Box::new(comparator));
+        self
+    }
+
+    /// Convenience for `sort_by`, ordering children by file name.
+    pub fn sort_by_file_name(self) -> Self {
+        self.sort_by(|a, b| a.file_name().cmp(&b.file_name()))
+    }
+
+    /// Construct the configured [`TreeWalker`].
+    pub fn build(self) -> io::Result<TreeWalker> {
+        let mut walker = TreeWalker::new(self.start)?;
+        walker.same_file_system = self.same_file_system;
+        walker.max_depth = self.max_depth;
+        walker.min_depth = self.min_depth;
+        walker.contents_first = self.contents_first;
+        walker.follow_links = self.follow_links;
+        walker.filter_entry = self.filter_entry;
+        walker.sort_by = self.sort_by;
         Ok(walker)
     }
 }
 
 impl Iterator for TreeWalker {
-    type Item = Result<DirEntry>;
+    type Item = Result<TreeEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // To avoid dead loop
@@ -92,53 +377,295 @@ impl Iterator for TreeWalker {
             return None;
         }
 
-        if let Some(entry) = self.stack.pop() {
-            let metadata = match entry.metadata() {
-                Ok(m) => m,
+        while let Some(pending) = self.stack.pop() {
+            let (entry, depth, ancestors) = match pending {
+                // A marker for a directory that has already had its
+                // children pushed: it is simply handed back to the caller.
+                Pending::Emit(entry, depth) => {
+                    if depth < self.min_depth {
+                        continue;
+                    }
+                    return Some(Ok(TreeEntry { entry, depth }));
+                }
+                Pending::Descend(entry, depth, ancestors) => (entry, depth, ancestors),
+            };
+
+            let file_type = match entry.file_type() {
+                Ok(ft) => ft,
                 Err(e) => {
-                    // This is a fatal error, since we need the metadata to
-                    // determine the file type
+                    // This is a fatal error, since we need the file type to
+                    // determine whether the entry is a directory or a symlink
                     self.fatal_error = true;
-                    return Some(Err(e));
+                    return Some(Err(e.into()));
                 }
             };
 
-            // If the popping node is a directory, push its files to the stack.
-            if metadata.is_dir() {
-                // To do a pre-order traversal, we have to use a temporary stack to
-                // reverse the order of its files.
-                let mut temp_stack = Vec::with_capacity(5);
+            // Symlinked directories are only descended into when
+            // `follow_links` is enabled; otherwise the entry is a leaf, just
+            // like a regular file.
+            let may_descend = !file_type.is_symlink() || self.follow_links;
 
-                let dir = match read_dir(entry.path()) {
-                    Ok(d) => d,
+            if may_descend {
+                // `DirEntry::metadata` does not traverse symlinks, so a
+                // symlinked directory would never look like one; resolve
+                // through it explicitly when we're meant to follow it.
+                let stat_result = if file_type.is_symlink() {
+                    metadata(entry.path())
+                } else {
+                    entry.metadata()
+                };
+                let metadata = match stat_result {
+                    Ok(m) => m,
+                    // A dangling symlink, or a permission error or race while
+                    // resolving one, is expected often enough that it isn't
+                    // fatal: report it for this entry only and keep walking
+                    // the rest of the tree, same as `Error::Loop`.
+                    Err(e) if file_type.is_symlink() => {
+                        return Some(Err(e.into()));
+                    }
                     Err(e) => {
-                        self.fatal_error = false;
-                        return Some(Err(e));
+                        // This is a fatal error, since we need the metadata to
+                        // determine the file type
+                        self.fatal_error = true;
+                        return Some(Err(e.into()));
                     }
                 };
 
-                for res_entry in dir {
-                    let entry = match res_entry {
-                        Ok(e) => e,
-                        Err(e) => {
-                            self.fatal_error = true;
-                            return Some(Err(e));
+                if metadata.is_dir() {
+                    // Prune the whole subtree before doing anything else:
+                    // neither yield nor descend into it.
+                    if self.filter_entry.as_mut().is_some_and(|filter| !filter(&entry)) {
+                        continue;
+                    }
+
+                    let id = file_id(&metadata);
+
+                    // A symlink resolving to a directory already on the
+                    // current path would recurse forever; report it instead.
+                    if file_type.is_symlink() && ancestors.contains(&id) {
+                        return Some(Err(Error::Loop(entry.path())));
+                    }
+
+                    // Push its children onto the stack, unless
+                    // `same_file_system` is set and this directory lives
+                    // on a different device than the root, or `max_depth`
+                    // forbids descending any further.
+                    if (!self.same_file_system || id.device == self.root_device)
+                        && self.max_depth.is_none_or(|max| depth < max)
+                    {
+                        // To do a pre-order traversal, we have to use a temporary stack to
+                        // reverse the order of its files.
+                        let mut temp_stack = Vec::with_capacity(5);
+
+                        let dir = match read_dir(entry.path()) {
+                            Ok(d) => d,
+                            Err(e) => {
+                                self.fatal_error = false;
+                                return Some(Err(e.into()));
+                            }
+                        };
+
+                        for res_entry in dir {
+                            let entry = match res_entry {
+                                Ok(e) => e,
+                                Err(e) => {
+                                    self.fatal_error = true;
+                                    return Some(Err(e.into()));
+                                }
+                            };
+
+                            temp_stack.push(entry);
                         }
-                    };
 
-                    temp_stack.push(entry);
-                }
+                        // Make the traversal order deterministic if the user
+                        // asked for it; `temp_stack` is popped below, so it
+                        // must be sorted ascending for children to come out
+                        // in that same order.
+                        if let Some(cmp) = self.sort_by.as_mut() {
+                            temp_stack.sort_by(|a, b| cmp(a, b));
+                        }
 
-                // push its files into the stack
-                while let Some(entry) = temp_stack.pop() {
-                    self.stack.push(entry);
+                        // Entries on this directory's path now also carry its
+                        // own identity, so loops through it can be spotted.
+                        // Only bother when `follow_links` is on: that's the
+                        // only mode that ever consults `ancestors`.
+                        let child_ancestors = if self.follow_links {
+                            let mut child_ancestors = (*ancestors).clone();
+                            child_ancestors.insert(id);
+                            Ancestors::new(child_ancestors)
+                        } else {
+                            ancestors.clone()
+                        };
+
+                        // In `contents_first` mode, push the directory back onto
+                        // the stack underneath its children, so it is only
+                        // emitted once all of them have been.
+                        if self.contents_first {
+                            self.stack.push(Pending::Emit(entry, depth));
+
+                            while let Some(child) = temp_stack.pop() {
+                                self.stack.push(Pending::Descend(
+                                    child,
+                                    depth + 1,
+                                    child_ancestors.clone(),
+                                ));
+                            }
+
+                            continue;
+                        } else {
+                            while let Some(child) = temp_stack.pop() {
+                                self.stack.push(Pending::Descend(
+                                    child,
+                                    depth + 1,
+                                    child_ancestors.clone(),
+                                ));
+                            }
+                        }
+                    }
                 }
             }
 
-            return Some(Ok(entry));
+            // Entries shallower than `min_depth` are traversed but not
+            // yielded.
+            if depth < self.min_depth {
+                continue;
+            }
+
+            return Some(Ok(TreeEntry { entry, depth }));
         }
 
         // stack is empty, traversal is done.
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+    /// A fresh, empty directory under the system temp dir, removed by the
+    /// caller once the test is done with it.
+    fn temp_dir(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, AtomicOrdering::Relaxed);
+        let dir = std::env::temp_dir().join(format!(
+            "treewalker_test_{name}_{}_{id}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn contents_first_yields_children_before_their_parent() {
+        let root = temp_dir("contents_first");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/file.txt"), "x").unwrap();
+
+        let paths: Vec<_> = TreeWalkerBuilder::new(&root)
+            .contents_first(true)
+            .sort_by_file_name()
+            .build()
+            .unwrap()
+            .map(|entry| entry.unwrap().into_inner().path())
+            .collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                root.join("a/b/file.txt"),
+                root.join("a/b"),
+                root.join("a"),
+                root.clone(),
+            ]
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn filter_entry_prunes_the_whole_subtree() {
+        let root = temp_dir("filter_entry");
+        fs::create_dir_all(root.join("keep")).unwrap();
+        fs::create_dir_all(root.join("skip/nested")).unwrap();
+        fs::write(root.join("skip/nested/file.txt"), "x").unwrap();
+
+        let paths: Vec<_> = TreeWalkerBuilder::new(&root)
+            .filter_entry(|entry| entry.file_name() != "skip")
+            .build()
+            .unwrap()
+            .map(|entry| entry.unwrap().into_inner().path())
+            .collect();
+
+        assert!(paths.contains(&root.join("keep")));
+        assert!(!paths.contains(&root.join("skip")));
+        assert!(!paths.contains(&root.join("skip/nested")));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn max_depth_and_min_depth_bound_the_traversal() {
+        let root = temp_dir("depth");
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+
+        let depths: Vec<_> = TreeWalkerBuilder::new(&root)
+            .min_depth(1)
+            .max_depth(2)
+            .build()
+            .unwrap()
+            .map(|entry| entry.unwrap().depth())
+            .collect();
+
+        assert!(!depths.is_empty());
+        assert!(depths.iter().all(|&depth| (1..=2).contains(&depth)));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_links_detects_a_loop() {
+        let root = temp_dir("follow_links_loop");
+        fs::create_dir_all(root.join("a")).unwrap();
+        std::os::unix::fs::symlink(root.join("a"), root.join("a/loop")).unwrap();
+
+        let error_count = TreeWalkerBuilder::new(&root)
+            .follow_links(true)
+            .build()
+            .unwrap()
+            .filter(Result::is_err)
+            .count();
+        assert_eq!(error_count, 1);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    /// A dangling symlink is reported as an error for that one entry, but
+    /// does not abort the rest of the traversal.
+    #[cfg(unix)]
+    #[test]
+    fn follow_links_survives_a_dangling_symlink() {
+        let root = temp_dir("follow_links_dangling");
+        fs::create_dir_all(root.join("a/after")).unwrap();
+        fs::write(root.join("a/after/file.txt"), "x").unwrap();
+        std::os::unix::fs::symlink(root.join("a/missing"), root.join("a/dangling")).unwrap();
+
+        let mut saw_error = false;
+        let mut saw_file_after = false;
+        for entry in TreeWalkerBuilder::new(&root).follow_links(true).build().unwrap() {
+            match entry {
+                Ok(e) if e.entry().path().ends_with("file.txt") => saw_file_after = true,
+                Err(_) => saw_error = true,
+                _ => {}
+            }
+        }
+        assert!(saw_error, "dangling symlink should be reported as an error");
+        assert!(saw_file_after, "traversal should continue past the error");
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}